@@ -6,18 +6,187 @@ use alloc::{boxed::Box, format, string::String, sync::Arc};
 use esp_idf_svc::{
     eth::{EthDriver, RmiiClockConfig, RmiiEth, RmiiEthChipset},
     eventloop::EspSystemEventLoop,
-    // === HERE: Import 'delay' which was already available via main.rs ===
     hal::{delay, gpio, modem::Modem, prelude::Peripherals},
     nvs::EspDefaultNvsPartition,
-    wifi::{AuthMethod, ClientConfiguration, Configuration, WifiDeviceId, WifiDriver},
+    sys::{
+        portMAX_DELAY, xSemaphoreCreateMutex, xSemaphoreGive, xSemaphoreTake, SemaphoreHandle_t,
+        TaskHandle_t,
+    },
+    wifi::{
+        AccessPointInfo, AuthMethod, ClientConfiguration, Configuration, WifiDeviceId, WifiDriver,
+    },
 };
 
 use once_cell::sync::OnceCell;
 
-// === HERE: Removed the old constants ===
-// const SSID: &str = env!("WIFI_SSID");
-// const PASS: &str = env!("WIFI_PASS");
-// const AUTH: AuthMethod = AuthMethod::WPA2Personal;
+use crate::forward::{self, FrameQueue};
+use crate::mac::SharedMacTable;
+use crate::provision::{self, Credential};
+
+/// Resolve the [`AuthMethod`] to connect with for one credential entry: an explicit
+/// `configured` override wins, then an empty password always means an open network,
+/// then the auth mode the scan reported for `ap` (when we saw it), falling back to
+/// `WPA2Personal` for hidden networks the scan never saw.
+fn resolve_auth(
+    pass: &str,
+    configured: Option<AuthMethod>,
+    ap: Option<&AccessPointInfo>,
+) -> AuthMethod {
+    if let Some(auth) = configured {
+        return auth;
+    }
+    if pass.is_empty() {
+        return AuthMethod::None;
+    }
+    ap.and_then(|ap| ap.auth_method)
+        .unwrap_or(AuthMethod::WPA2Personal)
+}
+
+/// A FreeRTOS mutex guarding every access (from any task) to one driver, so reconnects
+/// can't race the forwarding tasks' `send`/`is_connected` calls on the same driver.
+struct DriverLock(SemaphoreHandle_t);
+
+// SAFETY: a FreeRTOS mutex is designed to be taken and given from different tasks.
+unsafe impl Send for DriverLock {}
+unsafe impl Sync for DriverLock {}
+
+impl DriverLock {
+    fn new() -> Self {
+        // SAFETY: FFI call with no preconditions.
+        let handle = unsafe { xSemaphoreCreateMutex() };
+        assert!(!handle.is_null(), "Failed to create driver mutex!");
+        Self(handle)
+    }
+
+    /// Block until the lock is free, then hold it until the returned guard drops.
+    fn lock(&self) -> DriverLockGuard<'_> {
+        // SAFETY: `self.0` is a valid mutex handle for the lifetime of `self`.
+        unsafe { xSemaphoreTake(self.0, portMAX_DELAY) };
+        DriverLockGuard(self)
+    }
+}
+
+struct DriverLockGuard<'a>(&'a DriverLock);
+
+impl Drop for DriverLockGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: only ever released by the guard that took it in `DriverLock::lock`.
+        unsafe { xSemaphoreGive(self.0 .0) };
+    }
+}
+
+/// Scan for known networks, rank the ones seen by RSSI, and try each in turn (falling
+/// back to any configured SSID the scan missed) until one connects. Safe to call again
+/// after a disconnect: it starts `wifi` itself if a failed earlier call left it stopped,
+/// and touches nothing but `wifi`, so a caller like [`Bridge::<Running>::supervise`]
+/// keeps Ethernet and the STA MAC as they are. Holds `lock`
+/// for the whole call; see [`DriverLock`].
+fn connect_wifi(wifi: &mut WifiDriver<'static>, creds: &[Credential], lock: &DriverLock) -> bool {
+    let _guard = lock.lock();
+
+    if !wifi.is_started().unwrap_or(false) {
+        // A bare client config is enough to start Wi-Fi for scanning; the real
+        // per-network config is set just before each connect attempt below.
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+            .expect("Failed to set Wi-Fi configuration!");
+        wifi.start().expect("Failed to start Wi-Fi!");
+    }
+
+    log::info!("Scanning for known networks...");
+    let scan_results: alloc::vec::Vec<AccessPointInfo> = wifi.scan().unwrap_or_else(|e| {
+        log::warn!("Wi-Fi scan failed: {e}; falling back to sequential connect");
+        alloc::vec::Vec::new()
+    });
+
+    // Configured credentials the scan actually saw, strongest signal first.
+    let mut seen: alloc::vec::Vec<(&str, &str, AuthMethod, i8)> = creds
+        .iter()
+        .filter_map(|cred| {
+            if cred.ssid.is_empty() {
+                return None;
+            }
+            let ap = scan_results
+                .iter()
+                .find(|ap| ap.ssid.as_str() == cred.ssid)?;
+            Some((
+                cred.ssid.as_str(),
+                cred.pass.as_str(),
+                resolve_auth(&cred.pass, cred.auth, Some(ap)),
+                ap.signal_strength,
+            ))
+        })
+        .collect();
+    seen.sort_by_key(|&(_, _, _, rssi)| core::cmp::Reverse(rssi));
+
+    // Configured SSIDs the scan didn't see (e.g. hidden networks) keep the original
+    // list order as a fallback.
+    let hidden = creds.iter().filter_map(|cred| {
+        if cred.ssid.is_empty() || seen.iter().any(|&(s, ..)| s == cred.ssid) {
+            return None;
+        }
+        Some((
+            cred.ssid.as_str(),
+            cred.pass.as_str(),
+            resolve_auth(&cred.pass, cred.auth, None),
+        ))
+    });
+
+    let mut connected = false;
+
+    for (ssid, pass, auth) in seen
+        .iter()
+        .map(|&(ssid, pass, auth, _)| (ssid, pass, auth))
+        .chain(hidden)
+    {
+        log::info!("Attempting connection to WiFi: '{}'", ssid);
+
+        let (Ok(ssid), Ok(password)) = (ssid.try_into(), pass.try_into()) else {
+            // Shouldn't happen for anything that passed the `/save` handler's length
+            // checks, but a stale NVS entry from before that validation existed
+            // shouldn't panic the connect loop on every boot.
+            log::warn!("Skipping '{}': SSID/password too long for Wi-Fi config", ssid);
+            continue;
+        };
+        let wifi_config = Configuration::Client(ClientConfiguration {
+            ssid,
+            auth_method: auth,
+            password,
+            ..Default::default()
+        });
+
+        // `start()` isn't documented idempotent on an already-started driver, and the
+        // scan bootstrap above leaves it started for the first attempt here; stop it
+        // first so every attempt restarts from the same known state.
+        if wifi.is_started().unwrap_or(false) {
+            wifi.stop().expect("Failed to stop wifi");
+        }
+
+        wifi.set_configuration(&wifi_config)
+            .expect("Failed to set Wi-Fi configuration!");
+
+        wifi.start().expect("Failed to start Wi-Fi!");
+        wifi.connect().expect("Failed to start Wi-Fi connect");
+
+        log::info!("Waiting for connection...");
+        for _ in 0..100 {
+            // 10 second timeout
+            if wifi.is_connected().unwrap_or(false) {
+                connected = true;
+                log::info!("Successfully connected to: '{}'", ssid);
+                break;
+            }
+            delay::FreeRtos::delay_ms(100);
+        }
+
+        if connected {
+            break;
+        }
+        wifi.stop().expect("Failed to stop wifi");
+        log::warn!("Connection to '{}' failed. Trying next...", ssid);
+    }
+
+    connected
+}
 
 /// Wi-Fi to Ethernet Bridge State Machine
 pub struct Bridge<S> {
@@ -53,23 +222,37 @@ pub struct EthReady {
 /// Wi-Fi Ready State
 ///
 /// In this state, Wi-Fi is ready to be transitioned into the [`Running`] state.
-/// Notably, the Wi-Fi `Sta` MAC has been set to `client_mac`.
+/// Notably, the Wi-Fi `Sta` MAC has been set to `sta_mac`.
 pub struct WifiReady {
     eth: EthDriver<'static, RmiiEth>,
     wifi: WifiDriver<'static>,
+    sta_mac: [u8; 6],
+    nvs: Option<EspDefaultNvsPartition>,
 }
 
 /// Running State
 ///
 /// In this state, the bridge keeps the drivers on the heap so their addresses remain stable for
-/// the callbacks that forward frames between them.
+/// the callbacks that forward frames between them. Frames no longer flow synchronously inside
+/// those callbacks: each direction has a [`FrameQueue`] the callback pushes onto, drained by a
+/// dedicated forwarding task (see [`forward`]) so a burst of RX traffic or a transient link flap
+/// doesn't stall the driver's own RX context. A frame that arrives while its queue is full is
+/// dropped in the callback and counted (see [`FrameQueue::dropped`]).
 pub struct Running {
-    _eth: Box<EthDriver<'static, RmiiEth>>,
-    _wifi: Box<WifiDriver<'static>>,
+    eth: Box<EthDriver<'static, RmiiEth>>,
+    wifi: Box<WifiDriver<'static>>,
+    nvs: Option<EspDefaultNvsPartition>,
+    eth_to_wifi: &'static FrameQueue,
+    wifi_to_eth: &'static FrameQueue,
+    wifi_lock: &'static DriverLock,
+    eth_lock: &'static DriverLock,
+    _mac_table: &'static SharedMacTable,
+    _eth_to_wifi_task: TaskHandle_t,
+    _wifi_to_eth_task: TaskHandle_t,
 }
 
 impl Bridge<Idle> {
-    // ... (This function is unchanged from your original) ...
+    /// Take the peripherals, system event loop, and default NVS partition.
     pub fn new() -> Self {
         let peripherals = Peripherals::take().expect("Failed to take peripherals!");
         let sysloop = EspSystemEventLoop::take().expect("Failed to take sysloop!");
@@ -87,7 +270,6 @@ impl Bridge<Idle> {
 
 /// Transition from [`Idle`] to [`EthReady`].
 impl From<Bridge<Idle>> for Bridge<EthReady> {
-    // ... (This function is unchanged from your original) ...
     fn from(val: Bridge<Idle>) -> Self {
         let pins = val.state.peripherals.pins;
         let mut eth = EthDriver::new_rmii(
@@ -155,21 +337,21 @@ impl From<Bridge<Idle>> for Bridge<EthReady> {
 /// Transition from [`EthReady`] to [`WifiReady`].
 impl From<Bridge<EthReady>> for Bridge<WifiReady> {
     fn from(val: Bridge<EthReady>) -> Self {
-        // === HERE: Add 'mut' to fix the compiler error from step 81 ===
+        let nvs = val.state.nvs.clone();
         let mut wifi = WifiDriver::new(val.state.modem, val.state.sysloop.clone(), val.state.nvs)
             .expect("Failed to init WifiDriver!");
 
-        // === MODIFIED (THE FIX) ===
-        // We DO NOT set the configuration here. We just prepare the driver.
-        // We *must* set the MAC *before* starting.
+        // Configuration is set later, in `connect_wifi`; the MAC must be set before
+        // the driver starts, so we do that here and leave the driver otherwise bare.
         wifi.set_mac(WifiDeviceId::Sta, val.state.client_mac)
             .expect("Failed to set Wi-Fi MAC!");
-        // === END MODIFIED ===
 
         Self {
             state: WifiReady {
                 eth: val.state.eth,
                 wifi,
+                sta_mac: val.state.client_mac,
+                nvs,
             },
         }
     }
@@ -183,29 +365,29 @@ impl From<Bridge<WifiReady>> for Bridge<Running> {
         let mut eth = Box::new(val.state.eth);
         let mut wifi = Box::new(val.state.wifi);
 
-        // === MODIFIED (THE FIX) ===
-        // This is the correct logical order, as seen in the original:
-        // 1. Set callbacks
-        // 2. Start drivers
-        // 3. Connect (with fallback loop)
-        // === END MODIFIED ===
+        // Decoupling queues: the RX callbacks below only memcpy into these, the actual
+        // sends happen on the forwarding tasks spawned further down. Leaked rather than
+        // owned by value so the forwarding tasks (and the opposite driver's callback)
+        // can hold a plain `&'static FrameQueue` for as long as the bridge runs.
+        let eth_to_wifi: &'static FrameQueue = Box::leak(Box::new(FrameQueue::new()));
+        let wifi_to_eth: &'static FrameQueue = Box::leak(Box::new(FrameQueue::new()));
+
+        // Shared by both forwarding tasks below to NAT multiple Ethernet-side hosts
+        // behind the bridge's single cloned STA MAC; see `mac::SharedMacTable`.
+        let mac_table: &'static SharedMacTable =
+            Box::leak(Box::new(SharedMacTable::new(val.state.sta_mac)));
+
+        // Guard each driver against concurrent access from its forwarding task and
+        // `supervise`; see [`DriverLock`].
+        let wifi_lock: &'static DriverLock = Box::leak(Box::new(DriverLock::new()));
+        let eth_lock: &'static DriverLock = Box::leak(Box::new(DriverLock::new()));
 
-        // === STEP 1: Set up the callbacks (same as original code) ===
         let eth_ptr = &mut *eth as *mut EthDriver<'static, RmiiEth> as usize;
         unsafe {
             wifi.set_nonstatic_callbacks(
-                {
-                    let eth_ptr = eth_ptr;
-                    move |_, frame| {
-                        // SAFETY: eth stays alive while callbacks are registered
-                        let eth = &mut *(eth_ptr as *mut EthDriver<'static, RmiiEth>);
-                        if eth.is_connected().unwrap_or(false) {
-                            eth.send(frame.as_slice())?;
-                        } else {
-                            log::debug!("Ethernet not connected!");
-                        }
-                        Ok(())
-                    }
+                move |_, frame| {
+                    wifi_to_eth.push(frame.as_slice());
+                    Ok(())
                 },
                 |_, _, _| {},
             )
@@ -214,94 +396,139 @@ impl From<Bridge<WifiReady>> for Bridge<Running> {
 
         let wifi_ptr = &mut *wifi as *mut WifiDriver<'static> as usize;
         unsafe {
-            eth.set_nonstatic_rx_callback({
-                let wifi_ptr = wifi_ptr;
-                move |frame| {
-                    // SAFETY: wifi stays alive while callbacks are registered
-                    let wifi = &mut *(wifi_ptr as *mut WifiDriver<'static>);
-                    if wifi.is_connected().unwrap_or(false) {
-                        let _ = wifi.send(WifiDeviceId::Sta, frame.as_slice());
-                    } else {
-                        log::debug!("Wi-Fi not connected!");
-                    }
-                }
+            eth.set_nonstatic_rx_callback(move |frame| {
+                eth_to_wifi.push(frame.as_slice());
             })
             .expect("Failed to set Ethernet callback!");
         }
-        
-        // === STEP 2: Start Ethernet (same as original code) ===
-        // Ethernet was already started, but we do it again to match the original logic.
+
         eth.start().expect("Failed to start Ethernet!");
 
-        // === STEP 3: Start the Wi-Fi connection loop (NEW LOGIC) ===
-        // === HERE: Define credentials list ===
-        // We store the Options directly. This is allowed in a const context.
-        const CREDENTIALS: &[(Option<&str>, Option<&str>)] = &[
-            (
-                option_env!("WIFI_SSID_1"),
-                option_env!("WIFI_PASS_1"),
-            ),
-            (
-                option_env!("WIFI_SSID_2"),
-                option_env!("WIFI_PASS_2"),
-            ),
-        ];
+        let creds = provision::load_credentials(val.state.nvs.clone());
+        log::info!("Loaded {} credential(s) from NVS", creds.len());
+        if !connect_wifi(&mut wifi, &creds, wifi_lock) {
+            provision::run_captive_portal(&mut wifi, val.state.nvs);
+        }
 
-        let mut connected = false;
+        // Forwarding tasks: each pops from its queue and sends on the driver behind
+        // `eth_ptr`/`wifi_ptr`, parking while that driver's link is down.
+        let eth_to_wifi_task = forward::spawn_forwarder(
+            c"eth2wifi",
+            eth_to_wifi,
+            move || {
+                // SAFETY: `wifi` stays boxed in `Running` for this task's lifetime;
+                // `wifi_lock` rules out concurrent aliasing.
+                let _guard = wifi_lock.lock();
+                let wifi = unsafe { &*(wifi_ptr as *const WifiDriver<'static>) };
+                wifi.is_connected().unwrap_or(false)
+            },
+            move |frame| mac_table.translate_src(frame),
+            move |frame| {
+                // SAFETY: `wifi` stays boxed in `Running` for this task's lifetime;
+                // `wifi_lock` rules out concurrent aliasing.
+                let _guard = wifi_lock.lock();
+                let wifi = unsafe { &mut *(wifi_ptr as *mut WifiDriver<'static>) };
+                wifi.send(WifiDeviceId::Sta, frame)
+            },
+        );
+        let wifi_to_eth_task = forward::spawn_forwarder(
+            c"wifi2eth",
+            wifi_to_eth,
+            move || {
+                // SAFETY: `eth` stays boxed in `Running` for this task's lifetime;
+                // `eth_lock` rules out concurrent aliasing.
+                let _guard = eth_lock.lock();
+                let eth = unsafe { &*(eth_ptr as *const EthDriver<'static, RmiiEth>) };
+                eth.is_connected().unwrap_or(false)
+            },
+            move |frame| mac_table.restore_dst(frame),
+            move |frame| {
+                // SAFETY: `eth` stays boxed in `Running` for this task's lifetime;
+                // `eth_lock` rules out concurrent aliasing.
+                let _guard = eth_lock.lock();
+                let eth = unsafe { &mut *(eth_ptr as *mut EthDriver<'static, RmiiEth>) };
+                eth.send(frame)
+            },
+        );
 
-        for (ssid_opt, pass_opt) in CREDENTIALS.iter() {
-            let ssid = ssid_opt.unwrap_or("");
-            let pass = pass_opt.unwrap_or("");
+        log::info!("Bridge is running.");
 
-            if ssid.is_empty() {
-                continue;
-            }
+        Self {
+            state: Running {
+                eth,
+                wifi,
+                nvs: val.state.nvs,
+                eth_to_wifi,
+                wifi_to_eth,
+                wifi_lock,
+                eth_lock,
+                _mac_table: mac_table,
+                _eth_to_wifi_task: eth_to_wifi_task,
+                _wifi_to_eth_task: wifi_to_eth_task,
+            },
+        }
+    }
+}
 
-            log::info!("Attempting connection to WiFi: '{}'", ssid);
-
-            let wifi_config = Configuration::Client(ClientConfiguration {
-                ssid: ssid.try_into().unwrap(),
-                auth_method: AuthMethod::WPA2Personal,
-                password: pass.try_into().unwrap(),
-                ..Default::default()
-            });
-
-            wifi.set_configuration(&wifi_config)
-                .expect("Failed to set Wi-Fi configuration!");
-            
-            // This matches the original logic
-            wifi.start().expect("Failed to start Wi-Fi!");
-            wifi.connect().expect("Failed to start Wi-Fi connect");
-
-            log::info!("Waiting for connection...");
-            for _ in 0..100 { // 10 second timeout
-                if wifi.is_connected().unwrap_or(false) {
-                    connected = true;
-                    log::info!("Successfully connected to: '{}'", ssid);
-                    break;
-                }
-                delay::FreeRtos::delay_ms(100); // Use the delay from main.rs
+impl Bridge<Running> {
+    /// Poll both links forever, logging every up/down transition and, whenever Wi-Fi
+    /// drops, re-reading credentials from NVS and re-running [`connect_wifi`] without
+    /// touching Ethernet or the cloned STA MAC. Never returns: this is the bridge's
+    /// main loop once it's up.
+    pub fn supervise(mut self) -> ! {
+        let mut wifi_up = {
+            let _guard = self.state.wifi_lock.lock();
+            self.state.wifi.is_connected().unwrap_or(false)
+        };
+        let mut eth_up = {
+            let _guard = self.state.eth_lock.lock();
+            self.state.eth.is_connected().unwrap_or(false)
+        };
+        let mut reconnect_attempts: u32 = 0;
+
+        log::info!("Supervisor starting (Wi-Fi: {wifi_up}, Ethernet: {eth_up})");
+
+        loop {
+            delay::FreeRtos::delay_ms(1000);
+
+            let now_eth_up = {
+                let _guard = self.state.eth_lock.lock();
+                self.state.eth.is_connected().unwrap_or(false)
+            };
+            if now_eth_up != eth_up {
+                log::info!("Ethernet link {}", if now_eth_up { "up" } else { "down" });
+                eth_up = now_eth_up;
             }
 
-            if connected {
-                break; // Exit credentials loop
-            } else {
-                wifi.stop().expect("Failed to stop wifi");
-                log::warn!("Connection to '{}' failed. Trying next...", ssid);
+            let now_wifi_up = {
+                let _guard = self.state.wifi_lock.lock();
+                self.state.wifi.is_connected().unwrap_or(false)
+            };
+            if now_wifi_up != wifi_up {
+                log::info!("Wi-Fi link {}", if now_wifi_up { "up" } else { "down" });
+                wifi_up = now_wifi_up;
             }
-        }
 
-        if !connected {
-            panic!("Could not connect to ANY of the provided WiFi networks.");
-        }
-        
-        log::info!("Bridge is running.");
+            log::debug!(
+                "eth->wifi: {} forwarded, {} dropped; wifi->eth: {} forwarded, {} dropped",
+                self.state.eth_to_wifi.forwarded(),
+                self.state.eth_to_wifi.dropped(),
+                self.state.wifi_to_eth.forwarded(),
+                self.state.wifi_to_eth.dropped(),
+            );
 
-        Self {
-            state: Running {
-                _eth: eth,
-                _wifi: wifi,
-            },
+            if wifi_up {
+                continue;
+            }
+
+            reconnect_attempts += 1;
+            log::warn!("Wi-Fi down, reconnect attempt {reconnect_attempts}...");
+            let creds = provision::load_credentials(self.state.nvs.clone());
+            wifi_up = connect_wifi(&mut self.state.wifi, &creds, self.state.wifi_lock);
+            if wifi_up {
+                log::info!("Wi-Fi reconnected after {reconnect_attempts} attempt(s)");
+                reconnect_attempts = 0;
+            }
         }
     }
 }
@@ -0,0 +1,241 @@
+//! Bounded per-direction frame queues and the forwarding tasks that drain them.
+//!
+//! The Ethernet and Wi-Fi RX callbacks used to call `send` on the opposite driver
+//! directly, which ran the forwarding work inside the driver's own RX context and simply
+//! dropped frames whenever the destination link was down. [`FrameQueue`] decouples the
+//! two: a callback only has to `memcpy` the frame into a queue slot, and a dedicated
+//! [`spawn_forwarder`] task does the actual send, parking while the destination is
+//! unreachable and resuming once it comes back.
+
+extern crate alloc;
+use alloc::boxed::Box;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use esp_idf_svc::sys::{
+    pdMS_TO_TICKS, pdPASS, tskNO_AFFINITY, ulTaskNotifyTake, vTaskDelay, xTaskCreatePinnedToCore,
+    xTaskNotifyGive, EspError, TaskHandle_t,
+};
+
+/// Standard Ethernet MTU; each queued frame gets a slot this size.
+pub const MTU: usize = 1514;
+
+/// Slots per direction's [`FrameQueue`], per the classic eth2ap ring depth.
+pub const QUEUE_DEPTH: usize = 32;
+
+/// How long a forwarding task naps between polls of the destination link / queue, in
+/// milliseconds (converted to ticks via [`pdMS_TO_TICKS`] at each call site). Bounded
+/// rather than infinite so a notification lost to the startup race in
+/// [`spawn_forwarder`] only costs latency, never a stuck task.
+const POLL_MS: u32 = 100;
+
+/// An owned, fixed-size frame buffer, copied out of the driver's RX callback.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    len: u16,
+    buf: [u8; MTU],
+}
+
+impl Frame {
+    fn copy_from(data: &[u8]) -> Self {
+        let len = data.len().min(MTU);
+        let mut buf = [0u8; MTU];
+        buf[..len].copy_from_slice(&data[..len]);
+        Self {
+            len: len as u16,
+            buf,
+        }
+    }
+
+    /// Borrow the stored frame bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+
+    /// Mutably borrow the stored frame bytes, e.g. to rewrite a header in place before
+    /// forwarding it on.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len as usize]
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            buf: [0; MTU],
+        }
+    }
+}
+
+/// A bounded single-producer/single-consumer ring of [`Frame`] slots: the owning
+/// driver's RX callback is the sole producer, the task from [`spawn_forwarder`] the
+/// sole consumer.
+pub struct FrameQueue {
+    slots: Box<[core::cell::UnsafeCell<Frame>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    len: AtomicUsize,
+    dropped: AtomicU32,
+    forwarded: AtomicU32,
+    /// `TaskHandle_t` of the consumer task, filled in by [`spawn_forwarder`] once it
+    /// exists. Zero until then, in which case `push` skips the wakeup.
+    consumer: AtomicUsize,
+}
+
+// SAFETY: `push` only ever touches the slot at `head` and only ever advances `head`;
+// `pop` only ever touches the slot at `tail` and only ever advances `tail`. With a
+// single producer and single consumer those index sets never overlap a live write, so
+// sharing `&FrameQueue` across the two execution contexts is sound.
+unsafe impl Sync for FrameQueue {}
+
+impl FrameQueue {
+    pub fn new() -> Self {
+        let slots = (0..QUEUE_DEPTH)
+            .map(|_| core::cell::UnsafeCell::new(Frame::default()))
+            .collect::<alloc::vec::Vec<_>>()
+            .into_boxed_slice();
+        Self {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            dropped: AtomicU32::new(0),
+            forwarded: AtomicU32::new(0),
+            consumer: AtomicUsize::new(0),
+        }
+    }
+
+    /// Copy `data` into the next free slot. Returns `false` (and bumps [`Self::dropped`])
+    /// if the queue is full.
+    pub fn push(&self, data: &[u8]) -> bool {
+        if self.len.load(Ordering::Acquire) == QUEUE_DEPTH {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        // SAFETY: single-producer invariant documented on the `Sync` impl above.
+        unsafe { *self.slots[head].get() = Frame::copy_from(data) };
+        self.head.store((head + 1) % QUEUE_DEPTH, Ordering::Relaxed);
+        self.len.fetch_add(1, Ordering::Release);
+
+        let consumer = self.consumer.load(Ordering::Acquire);
+        if consumer != 0 {
+            // SAFETY: `consumer` is only ever set to a `TaskHandle_t` returned by
+            // `xTaskCreatePinnedToCore` in `spawn_forwarder`, and that task outlives
+            // this queue (see `Running`).
+            unsafe { xTaskNotifyGive(consumer as TaskHandle_t) };
+        }
+        true
+    }
+
+    /// Block for up to `ticks_to_wait` for a frame to become available, then pop it.
+    pub fn pop(&self, ticks_to_wait: u32) -> Option<Frame> {
+        if self.len.load(Ordering::Acquire) == 0 {
+            // SAFETY: FFI call with no preconditions beyond a valid tick count.
+            unsafe { ulTaskNotifyTake(1, ticks_to_wait) };
+        }
+        if self.len.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let tail = self.tail.load(Ordering::Relaxed);
+        // SAFETY: single-consumer invariant documented on the `Sync` impl above.
+        let frame = unsafe { *self.slots[tail].get() };
+        self.tail.store((tail + 1) % QUEUE_DEPTH, Ordering::Relaxed);
+        self.len.fetch_sub(1, Ordering::Release);
+        Some(frame)
+    }
+
+    /// Number of frames dropped so far because the queue was full.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames successfully handed off to the destination driver so far.
+    pub fn forwarded(&self) -> u32 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    fn mark_forwarded(&self) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn set_consumer(&self, task: TaskHandle_t) {
+        self.consumer.store(task as usize, Ordering::Release);
+    }
+}
+
+/// Spawn a FreeRTOS task that drains `queue`, applies `transform` to each frame, then
+/// hands it to `send` while `is_connected` reports the link up, parking otherwise.
+/// Runs for the lifetime of the program.
+pub fn spawn_forwarder<F, T, G>(
+    name: &core::ffi::CStr,
+    queue: &'static FrameQueue,
+    is_connected: F,
+    transform: T,
+    send: G,
+) -> TaskHandle_t
+where
+    F: Fn() -> bool + Send + 'static,
+    T: FnMut(&mut [u8]) + Send + 'static,
+    G: FnMut(&[u8]) -> Result<(), EspError> + Send + 'static,
+{
+    struct Ctx<F, T, G> {
+        queue: &'static FrameQueue,
+        is_connected: F,
+        transform: T,
+        send: G,
+    }
+
+    extern "C" fn trampoline<F, T, G>(arg: *mut c_void)
+    where
+        F: Fn() -> bool + Send + 'static,
+        T: FnMut(&mut [u8]) + Send + 'static,
+        G: FnMut(&[u8]) -> Result<(), EspError> + Send + 'static,
+    {
+        // SAFETY: `arg` is the `Box<Ctx<F, T, G>>` leaked via `Box::into_raw` below,
+        // and this trampoline is only ever installed as that one task's entry point.
+        let mut ctx = unsafe { Box::from_raw(arg.cast::<Ctx<F, T, G>>()) };
+        loop {
+            if !(ctx.is_connected)() {
+                // SAFETY: FFI call with no preconditions beyond a valid tick count.
+                unsafe { vTaskDelay(pdMS_TO_TICKS(POLL_MS)) };
+                continue;
+            }
+            if let Some(mut frame) = ctx.queue.pop(pdMS_TO_TICKS(POLL_MS)) {
+                (ctx.transform)(frame.as_mut_slice());
+                match (ctx.send)(frame.as_slice()) {
+                    Ok(()) => ctx.queue.mark_forwarded(),
+                    Err(e) => log::debug!("forward task: send failed: {e}"),
+                }
+            }
+        }
+    }
+
+    let ctx = Box::new(Ctx {
+        queue,
+        is_connected,
+        transform,
+        send,
+    });
+    let arg = Box::into_raw(ctx).cast::<c_void>();
+
+    let mut handle: TaskHandle_t = core::ptr::null_mut();
+    // SAFETY: `trampoline::<F, T, G>` matches the `pvParameters` we hand it (the
+    // `Ctx<F, T, G>` boxed above), the stack depth is nonzero, and `&mut handle` is a
+    // valid out-param for the duration of this call.
+    let created = unsafe {
+        xTaskCreatePinnedToCore(
+            Some(trampoline::<F, T, G>),
+            name.as_ptr(),
+            4096,
+            arg,
+            5,
+            &mut handle,
+            tskNO_AFFINITY as _,
+        )
+    };
+    assert_eq!(created, pdPASS as _, "Failed to create {name:?} forwarding task!");
+    queue.set_consumer(handle);
+    handle
+}
@@ -0,0 +1,159 @@
+//! Source-MAC translation so multiple hosts behind the Ethernet port can share the
+//! bridge's single Wi-Fi STA link.
+//!
+//! The AP only ever sees one STA MAC, so without translation only the first host sniffed
+//! onto that MAC is reachable. [`MacTable`] NATs around that: egress frames get their
+//! source MAC rewritten to the shared STA MAC, with the original recorded against the
+//! sender's IPv4 address; ingress frames get their destination MAC restored from that
+//! same table, keyed off the packet's target IPv4 address. Ingress frames with a
+//! broadcast/multicast destination are left untouched so they can still be flooded.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+/// Hosts tracked at once; least-recently-used entries are evicted first once full.
+const TABLE_SIZE: usize = 8;
+
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+
+/// One learned `IPv4 -> real MAC` mapping.
+struct Entry {
+    ip: [u8; 4],
+    mac: [u8; 6],
+}
+
+/// A small LRU table of `IPv4 -> real MAC` mappings for hosts seen on the Ethernet side.
+struct MacTable {
+    sta_mac: [u8; 6],
+    /// Most-recently-used entry first.
+    entries: Vec<Entry>,
+}
+
+impl MacTable {
+    fn new(sta_mac: [u8; 6]) -> Self {
+        Self {
+            sta_mac,
+            entries: Vec::with_capacity(TABLE_SIZE),
+        }
+    }
+
+    fn learn(&mut self, ip: [u8; 4], mac: [u8; 6]) {
+        if let Some(pos) = self.entries.iter().position(|e| e.ip == ip) {
+            let mut entry = self.entries.remove(pos);
+            entry.mac = mac;
+            self.entries.insert(0, entry);
+            return;
+        }
+        if self.entries.len() == TABLE_SIZE {
+            self.entries.pop(); // evict the least-recently-used host
+        }
+        self.entries.insert(0, Entry { ip, mac });
+    }
+
+    fn lookup(&mut self, ip: [u8; 4]) -> Option<[u8; 6]> {
+        let pos = self.entries.iter().position(|e| e.ip == ip)?;
+        let entry = self.entries.remove(pos);
+        self.entries.insert(0, entry);
+        Some(self.entries[0].mac)
+    }
+
+    /// Rewrite `frame`'s source MAC to the shared STA MAC, first recording the original
+    /// against the sender's IPv4 address (read from the IPv4 or ARP header) so
+    /// [`Self::restore_dst`] can undo it on the way back. For ARP frames the sender
+    /// hardware-address field is rewritten too, so the AP's peers learn the shared STA
+    /// MAC rather than the real one and actually route return traffic to us. No-op on
+    /// frames too short to carry an Ethernet header, or whose source is already the STA
+    /// MAC.
+    fn translate_src(&mut self, frame: &mut [u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        let src: [u8; 6] = frame[6..12].try_into().unwrap();
+        if src == self.sta_mac {
+            return;
+        }
+        if let Some(ip) = sender_ip(frame) {
+            self.learn(ip, src);
+        }
+        frame[6..12].copy_from_slice(&self.sta_mac);
+        if is_arp(frame) {
+            if let Some(sha) = frame.get_mut(22..28) {
+                sha.copy_from_slice(&self.sta_mac);
+            }
+        }
+    }
+
+    /// Restore the real destination MAC on a frame inbound from Wi-Fi, looked up by the
+    /// packet's target IPv4 address. For ARP frames the target hardware-address field is
+    /// restored too, undoing [`Self::translate_src`]'s SHA rewrite on the request this is
+    /// a reply to. Leaves broadcast/multicast destinations and not-yet-learned hosts
+    /// alone, so the caller floods them unchanged.
+    fn restore_dst(&mut self, frame: &mut [u8]) {
+        if frame.len() < 14 || is_broadcast_or_multicast(&frame[0..6]) {
+            return;
+        }
+        if let Some(ip) = target_ip(frame) {
+            if let Some(mac) = self.lookup(ip) {
+                frame[0..6].copy_from_slice(&mac);
+                if is_arp(frame) {
+                    if let Some(tha) = frame.get_mut(32..38) {
+                        tha.copy_from_slice(&mac);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_broadcast_or_multicast(dst: &[u8]) -> bool {
+    dst == [0xff; 6] || dst[0] & 1 != 0
+}
+
+fn is_arp(frame: &[u8]) -> bool {
+    frame.get(12..14) == Some(&ETHERTYPE_ARP[..])
+}
+
+/// Sender IPv4 address: the IPv4 source address for an IPv4 packet, or the ARP
+/// sender-IP field for an ARP packet.
+fn sender_ip(frame: &[u8]) -> Option<[u8; 4]> {
+    match frame.get(12..14)? {
+        x if *x == ETHERTYPE_IPV4 => frame.get(26..30)?.try_into().ok(),
+        x if *x == ETHERTYPE_ARP => frame.get(28..32)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Target IPv4 address: the IPv4 destination address for an IPv4 packet, or the ARP
+/// target-IP field for an ARP packet.
+fn target_ip(frame: &[u8]) -> Option<[u8; 4]> {
+    match frame.get(12..14)? {
+        x if *x == ETHERTYPE_IPV4 => frame.get(30..34)?.try_into().ok(),
+        x if *x == ETHERTYPE_ARP => frame.get(38..42)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// [`MacTable`] shared between the eth2wifi and wifi2eth forwarding tasks.
+pub struct SharedMacTable(Mutex<RefCell<MacTable>>);
+
+impl SharedMacTable {
+    pub fn new(sta_mac: [u8; 6]) -> Self {
+        Self(Mutex::new(RefCell::new(MacTable::new(sta_mac))))
+    }
+
+    /// See [`MacTable::translate_src`]. Called from the eth2wifi forwarding task just
+    /// before a frame is sent out over Wi-Fi.
+    pub fn translate_src(&self, frame: &mut [u8]) {
+        critical_section::with(|cs| self.0.borrow(cs).borrow_mut().translate_src(frame));
+    }
+
+    /// See [`MacTable::restore_dst`]. Called from the wifi2eth forwarding task just
+    /// before a frame is sent out over Ethernet.
+    pub fn restore_dst(&self, frame: &mut [u8]) {
+        critical_section::with(|cs| self.0.borrow(cs).borrow_mut().restore_dst(frame));
+    }
+}
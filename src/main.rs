@@ -3,9 +3,10 @@
 #![warn(clippy::undocumented_unsafe_blocks, clippy::pedantic, clippy::nursery)]
 #![no_std]
 
-use esp_idf_svc::hal::delay;
-
 mod bridge;
+mod forward;
+mod mac;
+mod provision;
 #[allow(clippy::wildcard_imports)]
 use bridge::*;
 
@@ -20,10 +21,7 @@ fn main() {
     let idle = Bridge::new();
     let ethup = Bridge::<EthReady>::from(idle);
     let wifiup = Bridge::<WifiReady>::from(ethup);
-    let _running = Bridge::<Running>::from(wifiup);
+    let running = Bridge::<Running>::from(wifiup);
 
-    // TODO
-    loop {
-        delay::FreeRtos::delay_ms(1000);
-    }
+    running.supervise();
 }
@@ -0,0 +1,233 @@
+//! NVS-backed Wi-Fi credential storage and SoftAP captive-portal provisioning.
+//!
+//! Credentials used to be baked in at compile time via `WIFI_SSID_n`/`WIFI_PASS_n` env
+//! vars, so changing networks meant a rebuild. They now live in the `wifi_cfg` NVS
+//! namespace instead: [`load_credentials`] reads whatever is stored there on boot, and
+//! if nothing stored connects, [`run_captive_portal`] repurposes the Wi-Fi driver as a
+//! temporary SoftAP serving a one-page setup form, persists whatever the user submits
+//! via [`save_credentials`], and reboots into bridge mode. Same firmware image, many
+//! units, reconfigurable in the field.
+
+extern crate alloc;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use esp_idf_svc::{
+    hal::delay,
+    http::{server::EspHttpServer, Method},
+    io::{Read as _, Write as _},
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    wifi::{AccessPointConfiguration, AuthMethod, Configuration, WifiDriver},
+};
+
+/// NVS namespace credentials are stored under.
+const NAMESPACE: &str = "wifi_cfg";
+
+/// Longest SSID/password `connect_wifi` can hand off, matching the capacity of the
+/// heapless `String<32>`/`String<64>` fields in `ClientConfiguration`. Enforced in the
+/// `/save` handler so an oversized submission is rejected here rather than saved and then
+/// skipped by `connect_wifi` on every boot.
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASS_LEN: usize = 64;
+
+/// SoftAP SSID/password the device advertises while it waits to be provisioned.
+const PORTAL_SSID: &str = "esp32-wifi-bridge-setup";
+const PORTAL_PASS: &str = "setup1234";
+
+/// One stored Wi-Fi network. `auth` of `None` means "derive it from the scan result",
+/// matching [`crate::bridge::resolve_auth`].
+#[derive(Clone)]
+pub struct Credential {
+    pub ssid: String,
+    pub pass: String,
+    pub auth: Option<AuthMethod>,
+}
+
+fn auth_to_tag(auth: Option<AuthMethod>) -> &'static str {
+    match auth {
+        Some(AuthMethod::None) => "none",
+        Some(AuthMethod::WEP) => "wep",
+        Some(AuthMethod::WPA) => "wpa",
+        Some(AuthMethod::WPA2Personal) => "wpa2personal",
+        Some(AuthMethod::WPAWPA2Personal) => "wpawpa2personal",
+        Some(AuthMethod::WPA3Personal) => "wpa3personal",
+        Some(AuthMethod::WPA2WPA3Personal) => "wpa2wpa3personal",
+        None | Some(_) => "auto",
+    }
+}
+
+fn tag_to_auth(tag: &str) -> Option<AuthMethod> {
+    match tag {
+        "none" => Some(AuthMethod::None),
+        "wep" => Some(AuthMethod::WEP),
+        "wpa" => Some(AuthMethod::WPA),
+        "wpa2personal" => Some(AuthMethod::WPA2Personal),
+        "wpawpa2personal" => Some(AuthMethod::WPAWPA2Personal),
+        "wpa3personal" => Some(AuthMethod::WPA3Personal),
+        "wpa2wpa3personal" => Some(AuthMethod::WPA2WPA3Personal),
+        _ => None,
+    }
+}
+
+fn get_stored_str(storage: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    let mut buf = [0u8; 128];
+    storage.get_str(key, &mut buf).ok().flatten().map(ToString::to_string)
+}
+
+/// Read every stored credential out of the `wifi_cfg` NVS namespace. Returns an empty
+/// list (not an error) if `nvs` is unavailable or the namespace has never been written.
+pub fn load_credentials(nvs: Option<EspDefaultNvsPartition>) -> Vec<Credential> {
+    let Some(nvs) = nvs else {
+        return Vec::new();
+    };
+    let Ok(storage) = EspNvs::new(nvs, NAMESPACE, true) else {
+        return Vec::new();
+    };
+    let count = storage.get_u8("count").ok().flatten().unwrap_or(0);
+
+    (0..count)
+        .filter_map(|i| {
+            let ssid = get_stored_str(&storage, &format!("ssid{i}"))?;
+            let pass = get_stored_str(&storage, &format!("pass{i}")).unwrap_or_default();
+            let auth = get_stored_str(&storage, &format!("auth{i}"))
+                .and_then(|tag| tag_to_auth(&tag));
+            Some(Credential { ssid, pass, auth })
+        })
+        .collect()
+}
+
+/// Persist `creds` to the `wifi_cfg` NVS namespace, replacing whatever was there.
+pub fn save_credentials(nvs: Option<EspDefaultNvsPartition>, creds: &[Credential]) {
+    let Some(nvs) = nvs else {
+        log::error!("No NVS partition available; can't persist credentials");
+        return;
+    };
+    let Ok(mut storage) = EspNvs::new(nvs, NAMESPACE, true) else {
+        log::error!("Failed to open NVS namespace '{NAMESPACE}' for writing");
+        return;
+    };
+
+    let count = creds.len().min(u8::MAX as usize) as u8;
+    if storage.set_u8("count", count).is_err() {
+        log::error!("Failed to persist credential count to NVS");
+        return;
+    }
+    for (i, cred) in creds.iter().take(count as usize).enumerate() {
+        let _ = storage.set_str(&format!("ssid{i}"), &cred.ssid);
+        let _ = storage.set_str(&format!("pass{i}"), &cred.pass);
+        let _ = storage.set_str(&format!("auth{i}"), auth_to_tag(cred.auth));
+    }
+    log::info!("Saved {count} credential(s) to NVS");
+}
+
+const PORTAL_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>esp32-wifi-bridge setup</title></head>
+<body>
+<h1>Wi-Fi setup</h1>
+<form method="POST" action="/save">
+<label>SSID: <input name="ssid" required></label><br>
+<label>Password: <input name="pass" type="password"></label><br>
+<button type="submit">Save &amp; Reboot</button>
+</form>
+</body>
+</html>"#;
+
+fn urldecode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '+' => out.push(' '),
+            '%' => match (chars.next(), chars.next()) {
+                (Some(hi), Some(lo)) => match u8::from_str_radix(&format!("{hi}{lo}"), 16) {
+                    Ok(byte) => out.push(byte as char),
+                    Err(_) => out.push('%'),
+                },
+                _ => out.push('%'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Pull one `application/x-www-form-urlencoded` field out of `body`.
+fn form_field(body: &str, key: &str) -> Option<String> {
+    body.split('&')
+        .find_map(|pair| pair.split_once('=').filter(|&(k, _)| k == key))
+        .map(|(_, v)| urldecode(v))
+}
+
+/// Repurpose `wifi` as a temporary SoftAP serving a minimal setup page at `/`, persist
+/// whatever SSID/password is POSTed to `/save` via [`save_credentials`], then reboot.
+/// Never returns.
+pub fn run_captive_portal(wifi: &mut WifiDriver<'static>, nvs: Option<EspDefaultNvsPartition>) -> ! {
+    log::warn!("No configured network reachable; starting setup AP '{PORTAL_SSID}'");
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: PORTAL_SSID.try_into().unwrap(),
+        password: PORTAL_PASS.try_into().unwrap(),
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    }))
+    .expect("Failed to configure setup AP!");
+    wifi.start().expect("Failed to start setup AP!");
+
+    let mut server = EspHttpServer::new(&Default::default())
+        .expect("Failed to start provisioning HTTP server!");
+
+    server
+        .fn_handler("/", Method::Get, |req| {
+            req.into_ok_response()?.write_all(PORTAL_PAGE.as_bytes())
+        })
+        .expect("Failed to register / handler!");
+
+    server
+        .fn_handler("/save", Method::Post, move |mut req| {
+            let mut body = [0u8; 512];
+            let len = req.read(&mut body).unwrap_or(0);
+            let form = String::from_utf8_lossy(&body[..len]);
+
+            let Some(ssid) = form_field(&form, "ssid").filter(|s| !s.is_empty()) else {
+                return req.into_status_response(400)?.write_all(b"missing ssid");
+            };
+            if ssid.len() > MAX_SSID_LEN {
+                return req
+                    .into_status_response(400)?
+                    .write_all(format!("ssid too long (max {MAX_SSID_LEN} bytes)").as_bytes());
+            }
+            let pass = form_field(&form, "pass").unwrap_or_default();
+            if pass.len() > MAX_PASS_LEN {
+                return req
+                    .into_status_response(400)?
+                    .write_all(format!("password too long (max {MAX_PASS_LEN} bytes)").as_bytes());
+            }
+
+            save_credentials(
+                nvs.clone(),
+                &[Credential {
+                    ssid,
+                    pass,
+                    auth: None,
+                }],
+            );
+            req.into_ok_response()?
+                .write_all(b"Saved. Rebooting into bridge mode...")?;
+
+            // Give the response time to flush before we pull the rug out.
+            delay::FreeRtos::delay_ms(500);
+            // SAFETY: `esp_restart` never returns; nothing after this point needs to
+            // keep running, so tearing down the HTTP server/AP first isn't necessary.
+            unsafe { esp_idf_svc::sys::esp_restart() }
+        })
+        .expect("Failed to register /save handler!");
+
+    log::info!("Setup AP ready; connect to '{PORTAL_SSID}' and browse to 192.168.71.1");
+    loop {
+        delay::FreeRtos::delay_ms(1000);
+    }
+}